@@ -1,20 +1,27 @@
 use log::{Level, debug, error, info, log_enabled, warn};
 
+use calloop::{EventLoop, Interest, LoopSignal, Mode, PostAction, generic::Generic};
+use calloop_wayland_source::WaylandSource;
 use clap::Parser;
 use env_logger::Env;
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
 use nix::sys::{
     signal::Signal,
     signal::Signal::*,
+    signal::kill,
     signalfd::{SfdFlags, SigSet, SignalFd},
-    wait::{WaitPidFlag, waitpid},
+    wait::{WaitPidFlag, WaitStatus, waitpid},
 };
+use nix::unistd::{Pid, dup2, execve, getpgid};
 use sd_notify;
+use std::ffi::CString;
 use std::fs;
 use std::io;
 use std::ops::Not;
-use std::os::fd::{AsFd, FromRawFd, OwnedFd};
-use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::{fs::FileTypeExt, net::UnixListener, process::CommandExt};
 use std::path;
+use std::process::Command;
 use wayland_client::{
     Connection, QueueHandle, delegate_noop,
     globals::{GlobalListContents, registry_queue_init},
@@ -25,6 +32,8 @@ use wayland_protocols::wp::security_context::v1::client::{
 };
 use xdg;
 
+mod xwayland;
+
 /// Set up a Wayland socket with an attached security context
 ///
 /// See https://wayland.app/protocols/security-context-v1
@@ -58,9 +67,40 @@ struct Cli {
     /// Receive socket via systemd socket activation (LISTEN_FDS)
     #[arg(long)]
     socket_activation: bool,
+    /// Manage a dedicated Xwayland display behind the tagged socket, so
+    /// sandboxed X11 clients get the same confinement as Wayland ones
+    #[arg(long)]
+    xwayland: bool,
+    /// Command to launch as the sandboxed child once the security context is
+    /// committed (e.g. `wlsctx ... -- sway`); its exit code becomes our own
+    #[arg(last = true)]
+    command: Vec<String>,
+}
+
+/// A listening socket paired with the security-context identity it should be
+/// tagged with.
+struct TaggedSocket {
+    app_id: String,
+    instance_id: String,
+    listener: UnixListener,
 }
 
-struct State;
+/// Shared state for both the Wayland dispatch callbacks and the calloop
+/// event sources driving them.
+struct State {
+    /// Pid of the supervised command, if any is configured. Tracked as a
+    /// bare pid rather than a `std::process::Child` so a SIGHUP restart can
+    /// adopt one that's still running from before the re-exec instead of
+    /// holding on to a `Child` handle that doesn't survive `execve`.
+    supervised_pid: Option<Pid>,
+    /// Pid and `DISPLAY` value of the managed Xwayland instance, if any; the
+    /// pid is also the process group the supervised command joins, so it is
+    /// the signal-forwarding target whenever Xwayland is running. Kept as one
+    /// field, not two, so the pid and its display can't drift out of sync.
+    xwayland: Option<(Pid, String)>,
+    exit_code: i32,
+    stop: LoopSignal,
+}
 
 impl wayland_client::Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
     fn event(
@@ -81,6 +121,249 @@ impl wayland_client::Dispatch<wl_registry::WlRegistry, GlobalListContents> for S
 delegate_noop!(State: wp_security_context_manager_v1::WpSecurityContextManagerV1);
 delegate_noop!(State: wp_security_context_v1::WpSecurityContextV1);
 
+/// fd 3 is the first descriptor systemd hands to an activated process
+/// (`SD_LISTEN_FDS_START`); we reuse the same convention when re-execing
+/// ourselves so the child can pick the socket up via `--socket-activation`.
+const LISTEN_FDS_START: i32 = 3;
+
+/// Re-exec the running binary in place, handing off the already-bound
+/// `sockets` through the systemd socket-activation protocol instead of
+/// closing and reopening them.
+///
+/// This is how the sockets survive a SIGHUP-triggered restart (e.g. after a
+/// config or version change) without ever dropping a tagged Wayland socket:
+/// the new process inherits fd 3, 4, ... with `LISTEN_FDS`/`LISTEN_PID`/
+/// `LISTEN_FDNAMES` set, re-binds the security contexts against them, and no
+/// client connection is lost in between.
+///
+/// `supervised_pid`, if the supervised command is still running, is passed
+/// through so the restarted process adopts it instead of spawning a second
+/// copy alongside the one that's already there. `xwayland` does the same
+/// for a managed Xwayland instance (its pid and `DISPLAY` value).
+fn reexec_with_listener(
+    sockets: Vec<TaggedSocket>,
+    supervised_pid: Option<Pid>,
+    xwayland: Option<(Pid, String)>,
+) -> ! {
+    let fdnames: Vec<String> = sockets
+        .iter()
+        .enumerate()
+        .map(|(i, socket)| {
+            let fd = socket.listener.as_fd().as_raw_fd();
+            // The listener must survive exec(2), so drop FD_CLOEXEC before
+            // moving it onto its well-known activation slot.
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                .expect("failed to clear FD_CLOEXEC on listener");
+            let slot = LISTEN_FDS_START + i as i32;
+            if fd != slot {
+                dup2(fd, slot).expect("failed to move listener to its activation slot");
+            }
+            format!("{}@{}", socket.app_id, socket.instance_id)
+        })
+        .collect();
+
+    // We blocked almost every signal to handle them via signalfd(2); restore
+    // the default mask before exec so the new process starts clean.
+    SigSet::all()
+        .thread_unblock()
+        .expect("failed to unblock signals before exec");
+
+    // Rewrite argv: drop any --listen/--socket-activation the caller passed
+    // (the socket is now arriving via LISTEN_FDS, not a path or a prior
+    // activation), then request socket activation ourselves. The flag must
+    // land before a trailing `--`, since clap treats anything after that as
+    // opaque positional data for the supervised command, not as our flags.
+    let mut args = std::env::args();
+    let mut new_argv = vec![args.next().expect("argv[0] missing")];
+    let mut requested_activation = false;
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            new_argv.push("--socket-activation".to_string());
+            requested_activation = true;
+            new_argv.push(arg);
+            new_argv.extend(args);
+            break;
+        }
+        if arg == "--listen" {
+            args.next();
+            continue;
+        }
+        if arg.starts_with("--listen=") || arg == "--socket-activation" {
+            continue;
+        }
+        new_argv.push(arg);
+    }
+    if !requested_activation {
+        new_argv.push("--socket-activation".to_string());
+    }
+    let argv: Vec<CString> = new_argv
+        .into_iter()
+        .map(|arg| CString::new(arg).expect("argv contained NUL"))
+        .collect();
+
+    // SAFETY: set_var/remove_var are safe here because we are single-threaded
+    // at this point, having just unblocked signals ourselves and not yet
+    // spawned anything else.
+    unsafe {
+        std::env::set_var("LISTEN_FDS", sockets.len().to_string());
+        std::env::set_var("LISTEN_PID", std::process::id().to_string());
+        std::env::set_var("LISTEN_FDNAMES", fdnames.join(":"));
+        // Tell the restarted process whether the supervised command and/or
+        // Xwayland are still running, so it adopts them instead of spawning
+        // second copies alongside the ones that are already there.
+        match supervised_pid {
+            Some(pid) => std::env::set_var("WLSCTX_SUPERVISED_PID", pid.to_string()),
+            None => std::env::remove_var("WLSCTX_SUPERVISED_PID"),
+        }
+        match xwayland {
+            Some((pid, display_var)) => {
+                std::env::set_var("WLSCTX_XWAYLAND_PID", pid.to_string());
+                std::env::set_var("WLSCTX_XWAYLAND_DISPLAY", display_var);
+            }
+            None => {
+                std::env::remove_var("WLSCTX_XWAYLAND_PID");
+                std::env::remove_var("WLSCTX_XWAYLAND_DISPLAY");
+            }
+        }
+        // The sockets are now only reachable via LISTEN_FDS; an inherited
+        // WLSCTX_SOCKET_PATH would otherwise make the restarted process
+        // believe it also has a --listen path configured, which takes
+        // priority over socket activation in main's match and rebinds a
+        // stale path out from under the inherited listener.
+        std::env::remove_var("WLSCTX_SOCKET_PATH");
+    }
+    let env: Vec<CString> = std::env::vars()
+        .map(|(key, value)| {
+            CString::new(format!("{key}={value}")).expect("environment contained NUL")
+        })
+        .collect();
+
+    let exe = CString::new("/proc/self/exe").unwrap();
+    let err = execve(&exe, &argv, &env).expect_err("execve returned on success");
+    panic!("execve failed during SIGHUP restart: {err}");
+}
+
+/// Derive `WAYLAND_DISPLAY`/`XDG_RUNTIME_DIR` for a supervised child so it
+/// connects through the tagged socket regardless of how it was opened.
+fn child_wayland_env(listener: &UnixListener) -> Vec<(&'static str, String)> {
+    match listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(path::Path::to_path_buf))
+    {
+        Some(socket_path) => {
+            let file_name = socket_path
+                .file_name()
+                .expect("socket path has no file name")
+                .to_string_lossy()
+                .into_owned();
+            let runtime_dir = socket_path
+                .parent()
+                .expect("socket path has no parent directory")
+                .to_string_lossy()
+                .into_owned();
+            vec![
+                ("WAYLAND_DISPLAY", file_name),
+                ("XDG_RUNTIME_DIR", runtime_dir),
+            ]
+        }
+        None => {
+            warn!("Tagged socket has no filesystem path; child inherits our environment as-is");
+            Vec::new()
+        }
+    }
+}
+
+/// Exit code to adopt as our own once the supervised child has gone away.
+fn exit_code_for(status: &WaitStatus) -> i32 {
+    match status {
+        WaitStatus::Exited(_, code) => *code,
+        WaitStatus::Signaled(_, signal, _) => 128 + *signal as i32,
+        _ => 0,
+    }
+}
+
+/// Handle one signalfd(2) reading. `sockets` is drained by a SIGHUP restart (which
+/// never returns); it is left alone otherwise.
+fn handle_signal(
+    siginfo: nix::libc::signalfd_siginfo,
+    state: &mut State,
+    sockets: &mut Option<Vec<TaggedSocket>>,
+) {
+    debug!("Signal: {siginfo:?}");
+    match Signal::try_from(siginfo.ssi_signo as i32).unwrap() {
+        signal @ (SIGTERM | SIGINT) => {
+            debug!("Stopping");
+            // The supervised command joins Xwayland's process group when
+            // both are running, so Xwayland's pid is the right group to
+            // signal whenever it's present; with no Xwayland, fall back to
+            // the supervised command's current group (which is still
+            // Xwayland's old one if Xwayland has already exited).
+            let pgid = state
+                .xwayland
+                .take()
+                .map(|(pid, _)| pid)
+                .or_else(|| {
+                    state
+                        .supervised_pid
+                        .take()
+                        .map(|pid| getpgid(Some(pid)).unwrap_or(pid))
+                });
+            if let Some(pgid) = pgid {
+                info!("Forwarding {signal:?} to sandboxed process group {pgid}");
+                let _ = kill(Pid::from_raw(-pgid.as_raw()), signal);
+            }
+            state.stop.stop();
+        }
+        SIGHUP => {
+            info!("Restarting via re-exec, keeping the tagged sockets alive");
+            let sockets = sockets.take().expect("sockets already consumed");
+            reexec_with_listener(sockets, state.supervised_pid, state.xwayland.clone());
+        }
+        SIGCHLD => {
+            debug!("reap zombies");
+            let mut main_child_exited = false;
+            while let Ok(status) = waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                debug!("status: {status:?}");
+                if status == WaitStatus::StillAlive {
+                    break;
+                }
+                let is_main_child = matches!(
+                    (state.supervised_pid, status.pid()),
+                    (Some(pid), Some(status_pid)) if status_pid.as_raw() == pid.as_raw()
+                );
+                if is_main_child {
+                    info!("Supervised child exited: {status:?}");
+                    state.exit_code = exit_code_for(&status);
+                    state.supervised_pid = None;
+                    main_child_exited = true;
+                }
+                let is_xwayland = matches!(
+                    (state.xwayland.as_ref(), status.pid()),
+                    (Some((pid, _)), Some(status_pid)) if status_pid.as_raw() == pid.as_raw()
+                );
+                if is_xwayland {
+                    // Clear it so a later SIGHUP restart doesn't try to adopt a
+                    // pid that's already gone (and so SIGTERM/SIGINT forwarding
+                    // falls back to the supervised command's process group).
+                    info!("Xwayland exited: {status:?}");
+                    state.xwayland = None;
+                }
+            }
+            if main_child_exited {
+                debug!("Supervised child is gone, shutting down");
+                state.stop.stop();
+            }
+        }
+        SIGTSTP | SIGTTOU | SIGTTIN => {
+            debug!("ignoring kernel attempting to stop us: tty has TOSTOP set");
+        }
+        sig => {
+            warn!("Unexpected signal ignored ({sig:#?})");
+        }
+    }
+}
+
 // The main function of our program
 fn main() {
     let env = Env::default().default_filter_or("warn");
@@ -88,35 +371,53 @@ fn main() {
     let cli = Cli::parse();
 
     let sandbox_engine = cli.sandbox_engine;
-    let (app_id, instance_id, listener) = match (cli.socket_activation, cli.listen) {
-        (true, None) => match sd_notify::listen_fds_with_names(true).map(|mut it| it.next()) {
-            Ok(Some((raw_fd, name))) => {
-                info!("Received socket {name} ({raw_fd:#?}) from parent");
-                let (app_id, instance_id) = match (cli.app_id, cli.instance_id) {
-                    (Some(app_id), Some(instance_id)) => (app_id, instance_id),
-                    (app_id, instance_id) => {
-                        match name.trim_end_matches(".socket").split_once('@') {
-                            Some((sd_prefix, sd_instance)) => (
-                                app_id.unwrap_or_else(|| sd_prefix.to_string()),
-                                instance_id.unwrap_or_else(|| sd_instance.to_string()),
-                            ),
-                            _ => {
+    let command = cli.command;
+    let sockets: Vec<TaggedSocket> = match (cli.socket_activation, cli.listen) {
+        (true, None) => {
+            // systemd routinely hands us more than one named fd (e.g. a
+            // per-app socket plus a control socket), so tag every one of
+            // them instead of just the first.
+            let fds = sd_notify::listen_fds_with_names(true)
+                .expect("Failed to get socket FDs from activation environment");
+            let sockets: Vec<TaggedSocket> = fds
+                .map(|(raw_fd, name)| {
+                    info!("Received socket {name} ({raw_fd:#?}) from parent");
+                    let (app_id, instance_id) = match name.trim_end_matches(".socket").split_once('@')
+                    {
+                        Some((sd_prefix, sd_instance)) => (
+                            cli.app_id.clone().unwrap_or_else(|| sd_prefix.to_string()),
+                            cli.instance_id
+                                .clone()
+                                .unwrap_or_else(|| sd_instance.to_string()),
+                        ),
+                        None => (
+                            cli.app_id.clone().unwrap_or_else(|| {
                                 panic!(
-                                    "Missing --app-id --instance-id and no LISTEN_FDNAMES= provided"
+                                    "Missing --app-id and no LISTEN_FDNAMES= provided for socket {name}"
                                 )
-                            }
-                        }
+                            }),
+                            cli.instance_id.clone().unwrap_or_else(|| {
+                                panic!(
+                                    "Missing --instance-id and no LISTEN_FDNAMES= provided for socket {name}"
+                                )
+                            }),
+                        ),
+                    };
+                    // SAFETY: sd_notify::listen_fds_with_names(true) unsets the LISTEN_FDS variable so we should be the
+                    // only user of this fd
+                    let listener = unsafe { UnixListener::from_raw_fd(raw_fd) };
+                    TaggedSocket {
+                        app_id,
+                        instance_id,
+                        listener,
                     }
-                };
-                // SAFETY: sd_notify::listen_fds_with_names(true) unsets the LISTEN_FDS variable so we should be the
-                // only user of this fd
-                let listener = unsafe { UnixListener::from_raw_fd(raw_fd) };
-                (app_id, instance_id, listener)
-            }
-            _ => {
+                })
+                .collect();
+            if sockets.is_empty() {
                 panic!("Failed to get socket FD from activation environment")
             }
-        },
+            sockets
+        }
         (_, Some(socket_path)) => {
             let socket_abspath = match socket_path.is_absolute() {
                 true => socket_path,
@@ -133,44 +434,170 @@ fn main() {
                         error!("Path already exists and is not a socket {socket_abspath:?}");
                     });
             });
-            (
-                cli.app_id.unwrap(),
-                cli.instance_id.unwrap(),
-                UnixListener::bind(socket_abspath).expect("Failed to bind to Unix socket"),
-            )
+            vec![TaggedSocket {
+                app_id: cli.app_id.unwrap(),
+                instance_id: cli.instance_id.unwrap(),
+                listener: UnixListener::bind(socket_abspath)
+                    .expect("Failed to bind to Unix socket"),
+            }]
         }
         _ => {
             panic!("No listening socket provided")
         }
     };
     if log_enabled!(Level::Info) {
-        if let Ok(local_addr) = listener.local_addr() {
-            info!("Listening on {local_addr:?}")
+        for socket in &sockets {
+            if let Ok(local_addr) = socket.listener.local_addr() {
+                info!(
+                    "Listening on {local_addr:?} for {} ({})",
+                    socket.app_id, socket.instance_id
+                )
+            }
         }
     }
 
-    let close_fd: OwnedFd = {
-        // Create a Wayland connection by connecting to the server through the
-        // environment-provided configuration.
-        let conn = Connection::connect_to_env().expect("upstream Wayland connection failed");
-        let (globals, mut event_queue) = registry_queue_init::<State>(&conn).unwrap();
-        let qh = &event_queue.handle();
-        let security_context_manager: wp_security_context_manager_v1::WpSecurityContextManagerV1 =
-            globals.bind(qh, 1..=1, ()).unwrap();
+    // Drive the whole program from a single calloop event loop: the Wayland
+    // connection and the signalfd are registered as sources below, so we
+    // notice compositor loss instead of blocking forever on a dead socket.
+    let mut event_loop: EventLoop<State> =
+        EventLoop::try_new().expect("failed to create event loop");
+    let loop_handle = event_loop.handle();
+    let mut state = State {
+        supervised_pid: None,
+        xwayland: None,
+        exit_code: 0,
+        stop: event_loop.get_signal(),
+    };
+
+    // Create a Wayland connection by connecting to the server through the
+    // environment-provided configuration, and keep both it and the event
+    // queue alive for the whole program so we notice if the compositor ever
+    // goes away.
+    let conn = Connection::connect_to_env().expect("upstream Wayland connection failed");
+    let (globals, mut event_queue) = registry_queue_init::<State>(&conn).unwrap();
+    let qh = &event_queue.handle();
+    let security_context_manager: wp_security_context_manager_v1::WpSecurityContextManagerV1 =
+        globals.bind(qh, 1..=1, ()).unwrap();
+
+    // Each listening socket gets its own security context and its own
+    // close_fd pipe; we keep every writer alive for the lifetime of the
+    // process so none of the contexts get torn down early.
+    let mut close_fds: Vec<OwnedFd> = Vec::with_capacity(sockets.len());
+    for socket in &sockets {
         let (reader, writer) = io::pipe().unwrap();
-        let security_context =
-            security_context_manager.create_listener(listener.as_fd(), reader.as_fd(), qh, ());
-        security_context_manager.destroy();
-        info!("Create security context mapping for {sandbox_engine} app: {app_id} ({instance_id})");
-        security_context.set_sandbox_engine(sandbox_engine);
-        security_context.set_app_id(app_id.clone());
-        security_context.set_instance_id(instance_id.clone());
+        let security_context = security_context_manager.create_listener(
+            socket.listener.as_fd(),
+            reader.as_fd(),
+            qh,
+            (),
+        );
+        info!(
+            "Create security context mapping for {sandbox_engine} app: {} ({})",
+            socket.app_id, socket.instance_id
+        );
+        security_context.set_sandbox_engine(sandbox_engine.clone());
+        security_context.set_app_id(socket.app_id.clone());
+        security_context.set_instance_id(socket.instance_id.clone());
         security_context.commit();
         security_context.destroy();
-        event_queue.roundtrip(&mut State {}).unwrap();
-        writer.into()
+        close_fds.push(writer.into());
+    }
+    security_context_manager.destroy();
+    event_queue.roundtrip(&mut state).unwrap();
+    info!(
+        "Holding {} close_fd(s) open to keep the tagged sockets available {close_fds:?}",
+        close_fds.len()
+    );
+
+    // A SIGHUP restart passes the pid (and, for Xwayland, the DISPLAY value)
+    // of anything still running through the environment; adopt them here
+    // instead of spawning second copies alongside the ones already there.
+    let inherited_supervised_pid = std::env::var("WLSCTX_SUPERVISED_PID")
+        .ok()
+        .and_then(|raw| raw.parse().ok())
+        .map(Pid::from_raw);
+    let inherited_xwayland = std::env::var("WLSCTX_XWAYLAND_PID")
+        .ok()
+        .and_then(|raw| raw.parse().ok())
+        .map(Pid::from_raw)
+        .zip(std::env::var("WLSCTX_XWAYLAND_DISPLAY").ok());
+    // SAFETY: we're still single-threaded here; now that these vars have
+    // been read, clear them so they don't leak into the supervised command's
+    // own environment below.
+    unsafe {
+        std::env::remove_var("WLSCTX_SUPERVISED_PID");
+        std::env::remove_var("WLSCTX_XWAYLAND_PID");
+        std::env::remove_var("WLSCTX_XWAYLAND_DISPLAY");
+    }
+
+    // With the context(s) committed, the Wayland socket is already confined;
+    // bring up a matching Xwayland behind it so X11 clients launched in the
+    // sandbox get the same treatment instead of reaching out over whatever
+    // display happens to be in the environment. The guard (if any) is kept
+    // around only to run its `Drop` before we exit; `state.xwayland` is what
+    // the rest of `main` actually uses.
+    let xwayland_guard = if let Some((pid, display_var)) = inherited_xwayland {
+        info!("Restart: Xwayland (pid {pid}) on display {display_var} is already running, not respawning it");
+        let number = display_var
+            .strip_prefix(':')
+            .and_then(|n| n.parse().ok())
+            .expect("WLSCTX_XWAYLAND_DISPLAY is always of the form :N");
+        state.xwayland = Some((pid, display_var));
+        Some(xwayland::X11Display::adopted(number))
+    } else if cli.xwayland {
+        let display = xwayland::allocate().expect("failed to reserve an X11 display");
+        // We track only its pid from here on (see `State::xwayland`); the
+        // global SIGCHLD handler would reap it via `waitpid` the same way it
+        // does the supervised command, independent of this `Child` handle,
+        // so dropping it here is intentional.
+        #[allow(clippy::zombie_processes)]
+        let xwayland_child = xwayland::spawn(&display, child_wayland_env(&sockets[0].listener))
+            .expect("failed to spawn Xwayland");
+        let pid = Pid::from_raw(xwayland_child.id() as i32);
+        state.xwayland = Some((pid, display.display_var()));
+        Some(display)
+    } else {
+        None
+    };
+
+    // wlsctx acts as the sandboxed app's init/supervisor: it is launched in
+    // its own process group so we can forward SIGTERM/SIGINT to it (and
+    // anything it spawns) as a unit, and its exit becomes our own. When
+    // multiple sockets are tagged, the first one is the one the child
+    // connects through. If Xwayland is managed, the child joins its process
+    // group instead of starting a new one, so a single signal reaches both.
+    state.supervised_pid = match inherited_supervised_pid {
+        Some(pid) => {
+            info!("Restart: supervised command (pid {pid}) is already running, not respawning it");
+            Some(pid)
+        }
+        None => match command.split_first() {
+            Some((program, args)) => {
+                let mut cmd = Command::new(program);
+                match &state.xwayland {
+                    Some((pid, display_var)) => {
+                        cmd.env("DISPLAY", display_var);
+                        cmd.process_group(pid.as_raw());
+                    }
+                    None => {
+                        cmd.process_group(0);
+                    }
+                }
+                cmd.args(args);
+                for (key, value) in child_wayland_env(&sockets[0].listener) {
+                    cmd.env(key, value);
+                }
+                info!("Launching supervised command: {command:?}");
+                // We track only its pid from here on (see `State::supervised_pid`);
+                // the global SIGCHLD handler reaps it via `waitpid`, independent
+                // of this `Child` handle, so dropping it here is intentional.
+                #[allow(clippy::zombie_processes)]
+                let child = cmd.spawn().expect("failed to spawn supervised command");
+                Some(Pid::from_raw(child.id() as i32))
+            }
+            None => None,
+        },
     };
-    info!("Holding close_fd open to keep the tagged Wayland socket available {close_fd:?}");
     let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
 
     // This signal handler is inspired by the implementation in catatonit:
@@ -187,32 +614,43 @@ fn main() {
         .collect();
     mask.thread_block().unwrap();
 
-    // Handle signals synchronously via signalfd(2)
-    let sigfd = SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC).unwrap();
-    while let Some(siginfo) = sigfd.read_signal().unwrap() {
-        debug!("Signal: {siginfo:?}");
-        match Signal::try_from(siginfo.ssi_signo as i32).unwrap() {
-            SIGTERM | SIGINT => {
-                debug!("Stopping");
-                break;
-            }
-            SIGHUP => {
-                warn!("TODO: SIGHUP restart");
-                break;
-            }
-            SIGCHLD => {
-                debug!("reap zombies");
-                while let Ok(status) = waitpid(None, Some(WaitPidFlag::WNOHANG)) {
-                    debug!("status: {status:?}");
+    // Handle signals via signalfd(2), driven by the same event loop as the
+    // Wayland connection. SFD_NONBLOCK lets the callback drain every queued
+    // signal without risking a blocking read once it catches up.
+    let sigfd =
+        SignalFd::with_flags(&mask, SfdFlags::SFD_CLOEXEC | SfdFlags::SFD_NONBLOCK).unwrap();
+
+    WaylandSource::new(conn, event_queue)
+        .insert(loop_handle.clone())
+        .expect("failed to register Wayland connection with the event loop");
+
+    let mut sockets = Some(sockets);
+    loop_handle
+        .insert_source(
+            Generic::new(sigfd, Interest::READ, Mode::Level),
+            move |_readiness, sigfd, state: &mut State| {
+                loop {
+                    match sigfd.read_signal() {
+                        Ok(Some(siginfo)) => handle_signal(siginfo, state, &mut sockets),
+                        Ok(None) => break,
+                        Err(nix::errno::Errno::EAGAIN) => break,
+                        Err(e) => return Err(io::Error::from(e)),
+                    }
                 }
-            }
-            SIGTSTP | SIGTTOU | SIGTTIN => {
-                debug!("ignoring kernel attempting to stop us: tty has TOSTOP set");
-            }
-            sig => {
-                warn!("Unexpected signal ignored ({sig:#?})");
-            }
-        }
+                Ok(PostAction::Continue)
+            },
+        )
+        .expect("failed to register signalfd with the event loop");
+
+    // Runs until a signal handler calls `state.stop.stop()`, or the
+    // connection to the compositor is lost (e.g. it crashed or sent us a
+    // protocol error) and the Wayland source errors out instead.
+    if let Err(e) = event_loop.run(None, &mut state, |_| {}) {
+        error!("Lost the compositor connection: {e}");
     }
     info!("Shutting down.");
+    // std::process::exit below skips destructors, so drop the X11 display
+    // guard by hand to make sure its lockfile and sockets are cleaned up.
+    drop(xwayland_guard);
+    std::process::exit(state.exit_code);
 }
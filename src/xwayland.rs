@@ -0,0 +1,181 @@
+//! Allocation of a dedicated X11 display slot for a rootless Xwayland
+//! instance running behind a security-context-tagged Wayland socket.
+
+use log::{info, warn};
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use nix::unistd::Pid;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr as UnixSocketAddr, UnixListener};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+
+/// Highest display number the classic X11 lockfile algorithm is willing to
+/// try before giving up.
+const MAX_DISPLAY: u32 = 32;
+
+/// A reserved X11 display slot: the lockfile and both listening sockets X
+/// clients expect (`/tmp/.X11-unix/X{n}` and its abstract-namespace twin)
+/// are held open for as long as this value lives. Dropping it releases all
+/// three.
+pub struct X11Display {
+    number: u32,
+    lock_path: PathBuf,
+    socket_path: PathBuf,
+    unix_listener: Option<UnixListener>,
+    abstract_listener: Option<UnixListener>,
+}
+
+impl X11Display {
+    /// The `DISPLAY` value legacy X11 clients should be given.
+    pub fn display_var(&self) -> String {
+        format!(":{}", self.number)
+    }
+
+    /// Reconstruct just the cleanup bookkeeping for a display number
+    /// allocated by an earlier process image. The listening sockets
+    /// themselves don't survive a SIGHUP re-exec (Xwayland, a separate
+    /// process, keeps its own copies), but the on-disk lock file and socket
+    /// still need removing once wlsctx is done with this display, so `Drop`
+    /// has to work without them.
+    pub fn adopted(number: u32) -> X11Display {
+        X11Display {
+            number,
+            lock_path: PathBuf::from(format!("/tmp/.X{number}-lock")),
+            socket_path: PathBuf::from(format!("/tmp/.X11-unix/X{number}")),
+            unix_listener: None,
+            abstract_listener: None,
+        }
+    }
+}
+
+impl Drop for X11Display {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.socket_path);
+        let _ = fs::remove_file(&self.lock_path);
+    }
+}
+
+/// Reserve the first free X11 display, using the same lockfile-then-bind
+/// algorithm Xorg/Xwayland use themselves: atomically create
+/// `/tmp/.X{n}-lock` for a candidate display number, and on success bind
+/// both sockets. Any failure along the way releases what was acquired for
+/// that number and moves on to the next one.
+pub fn allocate() -> io::Result<X11Display> {
+    for number in 0..=MAX_DISPLAY {
+        let lock_path = PathBuf::from(format!("/tmp/.X{number}-lock"));
+        let mut lock_file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                warn!("Display :{number} lockfile {lock_path:?} unavailable, trying the next one: {e}");
+                continue;
+            }
+        };
+        // Xorg/Xwayland write their PID padded to 11 bytes so tools like
+        // `xlsclients` can read the lockfile back; match that convention.
+        if let Err(e) = writeln!(lock_file, "{:>10}", Pid::this()) {
+            drop(lock_file);
+            warn!("Display :{number} lockfile {lock_path:?} not writable, trying the next one: {e}");
+            let _ = fs::remove_file(&lock_path);
+            continue;
+        }
+        drop(lock_file);
+
+        if let Err(e) = fs::create_dir_all("/tmp/.X11-unix") {
+            warn!("Display :{number} socket directory unavailable, trying the next one: {e}");
+            let _ = fs::remove_file(&lock_path);
+            continue;
+        }
+        let socket_path = PathBuf::from(format!("/tmp/.X11-unix/X{number}"));
+        let _ = fs::remove_file(&socket_path);
+        let unix_listener = match UnixListener::bind(&socket_path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Display :{number} socket {socket_path:?} busy, trying the next one: {e}");
+                let _ = fs::remove_file(&lock_path);
+                continue;
+            }
+        };
+        let abstract_addr = UnixSocketAddr::from_abstract_name(socket_path.as_os_str().as_encoded_bytes())
+            .expect("X11 socket path is a valid abstract socket name");
+        let abstract_listener = match UnixListener::bind_addr(&abstract_addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!(
+                    "Display :{number} abstract socket @{socket_path:?} busy, trying the next one: {e}"
+                );
+                let _ = fs::remove_file(&socket_path);
+                let _ = fs::remove_file(&lock_path);
+                continue;
+            }
+        };
+
+        return Ok(X11Display {
+            number,
+            lock_path,
+            socket_path,
+            unix_listener: Some(unix_listener),
+            abstract_listener: Some(abstract_listener),
+        });
+    }
+    Err(io::Error::other(format!(
+        "no free X11 display slot in 0..={MAX_DISPLAY}"
+    )))
+}
+
+/// Spawn `Xwayland -rootless` bound to `display`'s pre-opened listening
+/// sockets, starting it in its own process group so wlsctx can later forward
+/// signals to it the same way it does to the supervised command.
+///
+/// `wayland_env` should set `WAYLAND_DISPLAY`/`XDG_RUNTIME_DIR` so Xwayland
+/// connects to the compositor through the tagged Wayland socket rather than
+/// whatever it would otherwise inherit.
+pub fn spawn(
+    display: &X11Display,
+    wayland_env: impl IntoIterator<Item = (&'static str, String)>,
+) -> io::Result<Child> {
+    let unix_fd = display
+        .unix_listener
+        .as_ref()
+        .expect("a freshly allocated display always has live listener sockets")
+        .as_raw_fd();
+    let abstract_fd = display
+        .abstract_listener
+        .as_ref()
+        .expect("a freshly allocated display always has live listener sockets")
+        .as_raw_fd();
+
+    let mut cmd = Command::new("Xwayland");
+    cmd.arg("-rootless")
+        .arg("-listenfd")
+        .arg(unix_fd.to_string())
+        .arg("-listenfd")
+        .arg(abstract_fd.to_string())
+        .process_group(0);
+    for (key, value) in wayland_env {
+        cmd.env(key, value);
+    }
+    // The listening sockets must survive exec(2) to reach Xwayland, the same
+    // way the SIGHUP-restart listener does.
+    unsafe {
+        cmd.pre_exec(move || {
+            for fd in [unix_fd, abstract_fd] {
+                fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty())).map_err(io::Error::from)?;
+            }
+            Ok(())
+        });
+    }
+    info!(
+        "Launching Xwayland -rootless for display {}",
+        display.display_var()
+    );
+    cmd.spawn()
+}